@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
 use crate::network::{NetworkLayer, TCPManager};
@@ -7,19 +7,129 @@ use crate::storage::{LocalStorage, Storage};
 #[derive(Debug, Clone, PartialEq)]
 enum RaftState {
     Follower,
+    PreCandidate,
     Candidate,
     Leader,
 }
 
-#[derive(Debug, Clone)]
-enum MesageType {
-    RequestVote,
-    RequestVoteResponse,
-    AppendEntries,
-    AppendEntriesResponse,
-    Heartbeat,
-    HeartbeatResponse,
-    ClientRequest,
+/// Wire format for every node-to-node RPC.
+///
+/// `RPC_VERSION` is carried alongside the payload so that new fields or
+/// variants can be added without breaking peers running an older build:
+/// unknown versions are dropped rather than mis-parsed.
+const RPC_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum RpcMessage {
+    RequestVote { candidate_id: u32, term: u32, last_log_index: u32, last_log_term: u32 },
+    RequestVoteResponse { voter_id: u32, term: u32, vote_granted: bool },
+    AppendEntries { leader_id: u32, term: u32, prev_log_index: u32, prev_log_term: u32, commit_index: u32, entries: Vec<LogEntry> },
+    AppendEntriesResponse { sender_id: u32, term: u32, success: bool },
+    Heartbeat { leader_id: u32, term: u32 },
+    HeartbeatResponse { sender_id: u32, term: u32 },
+    ClientRequest { term: u32, command: LogCommand, key: u32, value: u32 },
+    ClientReadRequest { key: u32 },
+    ClientResponse { success: bool, value: Option<u32> },
+    InstallSnapshot { leader_id: u32, term: u32, last_included_index: u32, last_included_term: u32, state: Vec<u8>, cluster_config: Option<ClusterConfig> },
+    InstallSnapshotResponse { sender_id: u32, term: u32, success: bool },
+    PreVote { candidate_id: u32, term: u32, last_log_index: u32, last_log_term: u32 },
+    PreVoteResponse { voter_id: u32, term: u32, vote_granted: bool },
+}
+
+impl RpcMessage {
+    fn term(&self) -> u32 {
+        match self {
+            RpcMessage::RequestVote { term, .. } => *term,
+            RpcMessage::RequestVoteResponse { term, .. } => *term,
+            RpcMessage::AppendEntries { term, .. } => *term,
+            RpcMessage::AppendEntriesResponse { term, .. } => *term,
+            RpcMessage::Heartbeat { term, .. } => *term,
+            RpcMessage::HeartbeatResponse { term, .. } => *term,
+            RpcMessage::ClientRequest { term, .. } => *term,
+            RpcMessage::ClientReadRequest { .. } => 0,
+            RpcMessage::ClientResponse { .. } => 0,
+            RpcMessage::InstallSnapshot { term, .. } => *term,
+            RpcMessage::InstallSnapshotResponse { term, .. } => *term,
+            RpcMessage::PreVote { term, .. } => *term,
+            RpcMessage::PreVoteResponse { term, .. } => *term,
+        }
+    }
+}
+
+/// The state applied from the committed log. Kept behind a trait so the
+/// replicated log isn't tied to any one data model.
+trait StateMachine {
+    fn apply(&mut self, command: &LogCommand, key: u32, value: u32) -> Option<u32>;
+    fn get(&self, key: u32) -> Option<u32>;
+    fn snapshot(&self) -> Vec<u8>;
+    fn restore(&mut self, bytes: &[u8]);
+}
+
+/// Default `StateMachine`: an in-memory key-value store honoring `Set`/`Delete`.
+#[derive(Debug, Default)]
+struct KeyValueStore {
+    entries: HashMap<u32, u32>,
+}
+
+impl StateMachine for KeyValueStore {
+    fn apply(&mut self, command: &LogCommand, key: u32, value: u32) -> Option<u32> {
+        match command {
+            LogCommand::Set => {
+                self.entries.insert(key, value);
+                Some(value)
+            }
+            LogCommand::Delete => self.entries.remove(&key),
+            LogCommand::Noop | LogCommand::ConfigChange(_) => None,
+        }
+    }
+
+    fn get(&self, key: u32) -> Option<u32> {
+        self.entries.get(&key).copied()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        bincode::serialize(&self.entries).expect("KeyValueStore is always serializable")
+    }
+
+    fn restore(&mut self, bytes: &[u8]) {
+        self.entries = bincode::deserialize(bytes).unwrap_or_default();
+    }
+}
+
+/// Compacted log state up to and including `last_included_index`, persisted
+/// through `LocalStorage` so a lagging or newly-added follower can be caught
+/// up without replaying the full log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    last_included_index: u32,
+    last_included_term: u32,
+    state: Vec<u8>,
+    // Most recently applied cluster membership as of `last_included_index`,
+    // so a follower catching up via InstallSnapshot doesn't lose any
+    // ConfigChange entries that fell inside the compacted range.
+    cluster_config: Option<ClusterConfig>,
+}
+
+fn encode_message(message: &RpcMessage) -> Vec<u8> {
+    let payload = bincode::serialize(message).expect("RpcMessage is always serializable");
+    let mut framed = Vec::with_capacity(8 + payload.len());
+    framed.extend_from_slice(&RPC_VERSION.to_be_bytes());
+    framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&payload);
+    framed
+}
+
+fn decode_message(data: &[u8]) -> Result<RpcMessage, Box<bincode::ErrorKind>> {
+    let declared_len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    let payload = &data[8..];
+    if payload.len() != declared_len {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "length prefix says {} bytes but frame carries {}",
+            declared_len,
+            payload.len()
+        ))));
+    }
+    bincode::deserialize(payload)
 }
 
 #[derive(Debug)]
@@ -29,12 +139,30 @@ struct ServerState {
     voted_for: Option<u32>,
     log: Vec<LogEntry>,
     commit_index: u32,
-    previous_log_index: u32, 
-    next_index: Vec<u32>,
-    match_index: Vec<u32>,
+    previous_log_index: u32,
+    next_index: HashMap<u32, u32>,
+    match_index: HashMap<u32, u32>,
     election_timeout: Duration,
     last_heartbeat: Instant,
     votes_received: HashMap<u32, bool>,
+    // Absolute index/term of the most recent snapshot; log entries at or
+    // below last_included_index have been discarded in favour of `Snapshot`.
+    last_included_index: u32,
+    last_included_term: u32,
+    // Highest log index applied to the state machine.
+    last_applied: u32,
+    // Highest log index whose ConfigChange command (if any) has been applied.
+    last_applied_config_index: u32,
+    // Set while a C_old,new joint configuration entry is uncommitted or
+    // has committed but the follow-up C_new entry hasn't yet.
+    joint_config: Option<ClusterConfig>,
+    // Most recently applied cluster membership, regardless of whether it was
+    // joint or final. Carried into `Snapshot` so compaction doesn't lose
+    // membership history. `None` until the first ConfigChange applies.
+    current_cluster_config: Option<ClusterConfig>,
+    // Peers known to have acknowledged the current ReadIndex round, used to
+    // confirm leadership before serving a linearizable read.
+    read_index_acks: HashMap<u32, bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,14 +170,27 @@ enum LogCommand {
     Noop,
     Set,
     Delete,
+    ConfigChange(ClusterConfig),
+}
+
+/// A proposed (or committed) cluster membership. `old_members == new_members`
+/// once the transition is finalized; while they differ the entry represents
+/// the joint consensus period `C_old,new`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClusterConfig {
+    old_members: Vec<u32>,
+    new_members: Vec<u32>,
+    id_to_address_mapping: HashMap<u32, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct LogEntry {
+    index: u32,
     leader_id: u32,
     server_id: u32,
     term: u32,
     command: LogCommand,
+    key: u32,
     data: u32,
 }
 
@@ -63,6 +204,9 @@ pub struct ServerConfig {
     // Include default leader and leadership preferences
     pub default_leader: Option<u32>,
     pub leadership_preferences: HashMap<u32, u32>,
+    // Number of committed-but-unsnapshotted entries allowed to accumulate
+    // before the log is compacted into a snapshot.
+    pub snapshot_threshold: u32,
 }
 
 pub struct Server {
@@ -75,6 +219,7 @@ pub struct Server {
     write_buffer: Vec<LogEntry>,
     debounce_timer: Instant,
     storage: LocalStorage,
+    state_machine: Box<dyn StateMachine>,
 }
 
 impl Server {
@@ -87,11 +232,18 @@ impl Server {
             log: Vec::new(),
             commit_index: 0,
             previous_log_index: 0,
-            next_index: vec![0; peers.len()],
-            match_index: vec![0; peers.len()],
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
             election_timeout: config.election_timeout,
             last_heartbeat: Instant::now(),
             votes_received: HashMap::new(),
+            last_included_index: 0,
+            last_included_term: 0,
+            last_applied: 0,
+            last_applied_config_index: 0,
+            joint_config: None,
+            current_cluster_config: None,
+            read_index_acks: HashMap::new(),
         };
         let network_manager = TCPManager::new(config.address.clone(), config.port);
 
@@ -105,6 +257,7 @@ impl Server {
             write_buffer: Vec::new(),
             debounce_timer: Instant::now(),
             storage: LocalStorage::new(format!("server_{}.log", id)),
+            state_machine: Box::new(KeyValueStore::default()),
         }
     }
 
@@ -117,6 +270,7 @@ impl Server {
         loop {
             match self.state.state {
                 RaftState::Follower => self.follower().await,
+                RaftState::PreCandidate => self.pre_candidate().await,
                 RaftState::Candidate => self.candidate().await,
                 RaftState::Leader => self.leader().await,
             }
@@ -128,8 +282,8 @@ impl Server {
             return;
         }
 
-        self.state.match_index = vec![0; self.peers.len()+1];
-        self.state.next_index = vec![0; self.peers.len()+1];
+        self.state.match_index = HashMap::new();
+        self.state.next_index = HashMap::new();
 
         if self.state.current_term == 0 {
             self.state.current_term += 1;
@@ -147,12 +301,61 @@ impl Server {
 
         let now = Instant::now();
         if now.duration_since(self.state.last_heartbeat) > self.state.election_timeout {
-            self.state.state = RaftState::Candidate;
+            self.state.state = RaftState::PreCandidate;
             return;
         }
         self.receive_rpc().await;
     }
 
+    /// Pre-Vote phase (pre-https://raft.github.io/raft.pdf §9.6): probe for a
+    /// quorum willing to elect us *before* bumping `current_term`, so a node
+    /// that rejoins after a partition can't force the real leader to step
+    /// down just by cycling terms it can never win.
+    async fn pre_candidate(&mut self) {
+        if self.state.state != RaftState::PreCandidate {
+            return;
+        }
+
+        self.state.votes_received.clear();
+        self.state.votes_received.insert(self.id, true);
+
+        let pre_vote_term = self.state.current_term + 1;
+        let last_log_index = self.state.previous_log_index;
+        let last_log_term = self.term_at(last_log_index);
+        let data = self.prepare_pre_vote(pre_vote_term, last_log_index, last_log_term);
+        let addresses: Vec<String> = self.peers.iter().map(|peer_id| {
+            self.config.id_to_address_mapping.get(peer_id).unwrap().clone()
+        }).collect();
+        if let Err(e) = self.network_manager.broadcast(&data, addresses).await {
+            eprintln!("Failed to send pre-votes: {}", e);
+        }
+
+        // We only get here once last_heartbeat is already older than the
+        // election timeout, so time the round from its own start.
+        let round_start = Instant::now();
+        while round_start.elapsed() < self.state.election_timeout {
+            self.receive_rpc().await;
+            if self.has_vote_quorum() {
+                break;
+            }
+        }
+
+        self.conclude_pre_vote_round();
+    }
+
+    /// Promote to `Candidate` if the pre-vote round reached quorum, otherwise
+    /// fall back to `Follower`. Split out from `pre_candidate` so the
+    /// decision (and its vote-clearing-on-failure-only behaviour) can be
+    /// tested without driving a real network round.
+    fn conclude_pre_vote_round(&mut self) {
+        if self.has_vote_quorum() {
+            self.state.state = RaftState::Candidate;
+        } else {
+            self.state.votes_received.clear();
+            self.state.state = RaftState::Follower;
+        }
+    }
+
     async fn candidate(&mut self) {
         if self.state.state != RaftState::Candidate {
             return;
@@ -166,7 +369,9 @@ impl Server {
         self.state.votes_received.insert(self.id, true);
 
         // TODO: Send RequestVote RPCs with leadership preferences
-        let data = self.prepare_request_vote(self.id, self.state.current_term);
+        let last_log_index = self.state.previous_log_index;
+        let last_log_term = self.term_at(last_log_index);
+        let data = self.prepare_request_vote(self.id, self.state.current_term, last_log_index, last_log_term);
         let addresses: Vec<String> = self.peers.iter().map(|peer_id| {
             self.config.id_to_address_mapping.get(peer_id).unwrap().clone()
         }).collect();
@@ -175,18 +380,18 @@ impl Server {
         let now = Instant::now();
         while now.duration_since(self.state.last_heartbeat) < self.state.election_timeout {
             self.receive_rpc().await;
-            if self.is_quorum(self.state.votes_received.len() as u32) {
+            if self.has_vote_quorum() {
                 break;
             }
         }
 
-        if self.is_quorum(self.state.votes_received.len() as u32) {
+        if self.has_vote_quorum() {
             self.state.state = RaftState::Leader;
         } else {
             self.state.votes_received.clear();
             self.state.state = RaftState::Follower;
         }
-        
+
     }
 
     async fn leader(&mut self) {
@@ -214,13 +419,13 @@ impl Server {
         // TODO: Write coalescing with debouncing
         // Move this to a separate thread to avoid blocking the main loop
         if !self.write_buffer.is_empty() {
-            let append_batch = self.prepare_append_batch(self.id, self.state.current_term, self.state.previous_log_index, self.state.commit_index, self.write_buffer.clone());
-
-            for entry in self.write_buffer.clone() {
-                let data = [2u32.to_be_bytes(), entry.data.to_be_bytes()].concat();
-                self.append_log(self.id, self.state.current_term, &data).await;
-            }
+            let entries = self.write_buffer.clone();
+            let sent_indices: HashSet<u32> = entries.iter().map(|e| e.index).collect();
+            let prev_log_index = entries.first().map(|e| e.index - 1).unwrap_or(self.state.previous_log_index);
+            let prev_log_term = self.term_at(prev_log_index);
+            self.append_log(entries.clone()).await;
 
+            let append_batch = self.prepare_append_batch(self.id, self.state.current_term, prev_log_index, prev_log_term, self.state.commit_index, entries);
             let addresses: Vec<String> = self.peers.iter().map(|peer_id| {
                 self.config.id_to_address_mapping.get(peer_id).unwrap().clone()
             }).collect();
@@ -232,7 +437,9 @@ impl Server {
             println!("Waiting for consensus");
             let commit_index = self.state.commit_index;
             println!("commit_index: {}", commit_index);
-            // Wait for consensus until term changes
+            // Keep retrying (via handle_append_entries_response's
+            // retry_append_entries) until a quorum acks and commit_index
+            // advances, or we give up and step down.
             while self.state.commit_index == commit_index {
                 println!("inside while loop {}", self.state.current_term);
                 if now.duration_since(self.state.last_heartbeat) > self.state.election_timeout {
@@ -241,205 +448,357 @@ impl Server {
                     return;
                 }
                 self.receive_rpc().await;
-                break;
             }
             println!("Consensus reached");
             println!("Current term: {}", self.state.current_term);
             println!("commit index: {}", self.state.commit_index);
-            self.write_buffer.clear();
-            self.debounce_timer = Instant::now();            
+            // Only drop the entries this round actually sent. Committing
+            // them can, via apply_config_entries_up_to below, enqueue a
+            // finalizing ConfigChange entry onto write_buffer while we were
+            // waiting above (through handle_append_entries_response) —
+            // clearing the whole buffer would silently drop that entry
+            // before it's ever replicated.
+            self.write_buffer.retain(|entry| !sent_indices.contains(&entry.index));
+            self.debounce_timer = Instant::now();
+
+            let succeeded = self.state.commit_index > commit_index;
+            self.apply_config_entries_up_to(self.state.commit_index);
+            let applied_value = self.apply_committed_entries().await;
+            self.compact_log_if_needed().await;
+            self.send_client_response(succeeded, applied_value).await;
         }
-
-        // broadcast on your own address so client can get response
-        let response_data = [1u32.to_be_bytes()].concat();
-        if let Err(e) = self.network_manager.send(self.config.address.as_str(), self.config.port.to_string().as_str(), &response_data).await {
-            eprintln!("Failed to send client response: {}", e);
-        }
-
     }
-    
+
     async fn receive_rpc(&mut self) {
         let data = self.network_manager.receive().await.unwrap();
         self.handle_rpc(data).await;
     }
 
-    fn prepare_append_batch(&self, id: u32, term: u32, prev_log_index: u32, commit_index: u32, write_buffer: Vec<LogEntry>) -> Vec<u8> {
-        let mut data = [id.to_be_bytes(), term.to_be_bytes(), 2u32.to_be_bytes(), prev_log_index.to_be_bytes(), commit_index.to_be_bytes()].concat();
-        for entry in write_buffer {
-            let entry_data = [entry.term.to_be_bytes(), entry.data.to_be_bytes()].concat();
-            data.extend_from_slice(&entry_data);
-        }
-        data
+    fn prepare_append_batch(&self, id: u32, term: u32, prev_log_index: u32, prev_log_term: u32, commit_index: u32, entries: Vec<LogEntry>) -> Vec<u8> {
+        encode_message(&RpcMessage::AppendEntries { leader_id: id, term, prev_log_index, prev_log_term, commit_index, entries })
+    }
+
+    fn prepare_request_vote(&self, id: u32, term: u32, last_log_index: u32, last_log_term: u32) -> Vec<u8> {
+        encode_message(&RpcMessage::RequestVote { candidate_id: id, term, last_log_index, last_log_term })
     }
 
-    fn prepare_request_vote(&self, id: u32, term: u32) -> Vec<u8> {
-        [id.to_be_bytes(), term.to_be_bytes(), 0u32.to_be_bytes()].concat()
+    fn prepare_pre_vote(&self, term: u32, last_log_index: u32, last_log_term: u32) -> Vec<u8> {
+        encode_message(&RpcMessage::PreVote { candidate_id: self.id, term, last_log_index, last_log_term })
     }
 
     fn prepare_heartbeat(&self) -> Vec<u8> {
-        [self.id.to_be_bytes(), self.state.current_term.to_be_bytes(), 4u32.to_be_bytes()].concat()
+        encode_message(&RpcMessage::Heartbeat { leader_id: self.id, term: self.state.current_term })
     }
 
     async fn handle_rpc(&mut self, data: Vec<u8>) {
-        let term = u32::from_be_bytes(data[4..8].try_into().unwrap());
-        let message_type: u32 = u32::from_be_bytes(data[8..12].try_into().unwrap());
+        if data.len() < 8 {
+            eprintln!("Dropping undersized RPC frame ({} bytes)", data.len());
+            return;
+        }
 
-        if term < self.state.current_term {
+        let version = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        if version != RPC_VERSION {
+            eprintln!("Dropping RPC frame with unsupported version {}", version);
             return;
         }
 
-        let message_type = match message_type {
-            0 => MesageType::RequestVote,
-            1 => MesageType::RequestVoteResponse,
-            2 => MesageType::AppendEntries,
-            3 => MesageType::AppendEntriesResponse,
-            4 => MesageType::Heartbeat,
-            5 => MesageType::HeartbeatResponse,
-            6 => MesageType::ClientRequest,
-            _ => return,
+        let message = match decode_message(&data) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to decode RPC frame: {}", e);
+                return;
+            }
         };
-        
-        match message_type {
-            MesageType::RequestVote => {
-                self.handle_request_vote(&data).await;
+
+        // Client-originated messages don't carry a meaningful term (the
+        // client doesn't track one), so the staleness filter below only
+        // applies to RPCs exchanged between term-aware peers.
+        let is_client_message = matches!(message, RpcMessage::ClientRequest { .. } | RpcMessage::ClientReadRequest { .. });
+        if !is_client_message && message.term() < self.state.current_term {
+            return;
+        }
+
+        match message {
+            RpcMessage::RequestVote { candidate_id, term, last_log_index, last_log_term } => {
+                self.handle_request_vote(candidate_id, term, last_log_index, last_log_term).await;
+            }
+            RpcMessage::RequestVoteResponse { voter_id, vote_granted, .. } => {
+                self.handle_request_vote_response(voter_id, vote_granted).await;
+            }
+            RpcMessage::AppendEntries { leader_id, term, prev_log_index, prev_log_term, commit_index, entries } => {
+                self.handle_append_entries(leader_id, term, prev_log_index, prev_log_term, commit_index, entries).await;
+            }
+            RpcMessage::AppendEntriesResponse { sender_id, term, success } => {
+                self.handle_append_entries_response(sender_id, term, success).await;
+            }
+            RpcMessage::Heartbeat { leader_id, .. } => {
+                self.handle_heartbeat(leader_id).await;
+            }
+            RpcMessage::HeartbeatResponse { sender_id, .. } => {
+                self.handle_heartbeat_response(sender_id).await;
+            }
+            RpcMessage::ClientRequest { command, key, value, .. } => {
+                self.handle_client_request(command, key, value).await;
             }
-            MesageType::RequestVoteResponse => {
-                self.handle_request_vote_response(&data).await;
+            RpcMessage::ClientReadRequest { key } => {
+                self.handle_client_read_request(key).await;
             }
-            MesageType::AppendEntries => {
-                self.handle_append_entries(data).await;
+            RpcMessage::ClientResponse { .. } => {
+                // Sent by the leader on its own address for a waiting client
+                // (see send_client_response); nothing for a server to act on.
             }
-            MesageType::AppendEntriesResponse => {
-                self.handle_append_entries_response(&data).await;
+            RpcMessage::InstallSnapshot { leader_id, term, last_included_index, last_included_term, state, cluster_config } => {
+                self.handle_install_snapshot(leader_id, term, last_included_index, last_included_term, state, cluster_config).await;
             }
-            MesageType::Heartbeat => {
-                self.handle_heartbeat().await;
+            RpcMessage::InstallSnapshotResponse { sender_id, term, success } => {
+                self.handle_install_snapshot_response(sender_id, term, success).await;
             }
-            MesageType::HeartbeatResponse => {
-                self.handle_heartbeat_response().await;
+            RpcMessage::PreVote { candidate_id, term, last_log_index, last_log_term } => {
+                self.handle_pre_vote(candidate_id, term, last_log_index, last_log_term).await;
             }
-            MesageType::ClientRequest => {
-                self.handle_client_request(data).await;
+            RpcMessage::PreVoteResponse { voter_id, vote_granted, .. } => {
+                self.handle_pre_vote_response(voter_id, vote_granted).await;
             }
         }
     }
 
-    async fn handle_client_request(&mut self, data: Vec<u8>) {
+    async fn handle_client_request(&mut self, command: LogCommand, key: u32, value: u32) {
         if self.state.state != RaftState::Leader {
             return;
         }
 
         let term = self.state.current_term;
-        let command = LogCommand::Set;
-        let data = u32::from_be_bytes(data[12..16].try_into().unwrap());
-        let entry = LogEntry { leader_id: self.id, server_id: self.id, term, command, data };
-        println!("Received client request: {:?}", entry);
         self.state.previous_log_index += 1;
-        self.state.commit_index += 1;
-        self.state.current_term += 1;
+        let entry = LogEntry { index: self.state.previous_log_index, leader_id: self.id, server_id: self.id, term, command, key, data: value };
+        println!("Received client request: {:?}", entry);
+        // commit_index only advances once a quorum acknowledges the entry,
+        // see handle_append_entries_response.
         self.write_buffer.push(entry);
     }
 
-    async fn handle_request_vote(&mut self, data: &[u8]) {
+    /// Serve a linearizable read (Raft §8 ReadIndex): confirm leadership by
+    /// collecting a heartbeat-ack quorum for the current commit index, wait
+    /// for the state machine to catch up to it, then read locally.
+    async fn handle_client_read_request(&mut self, key: u32) {
+        if self.state.state != RaftState::Leader {
+            self.send_client_response(false, None).await;
+            return;
+        }
+
+        let read_index = self.state.commit_index;
+        self.state.read_index_acks.clear();
+        self.state.read_index_acks.insert(self.id, true);
+
+        let heartbeat_data = self.prepare_heartbeat();
+        let addresses: Vec<String> = self.peers.iter().map(|peer_id| {
+            self.config.id_to_address_mapping.get(peer_id).unwrap().clone()
+        }).collect();
+        if let Err(e) = self.network_manager.broadcast(&heartbeat_data, addresses).await {
+            eprintln!("Failed to send heartbeats for read index: {}", e);
+        }
+
+        // handle_rpc can dispatch back into this function for another
+        // ClientReadRequest, so the recursive call needs boxing.
+        let now = Instant::now();
+        while now.duration_since(self.state.last_heartbeat) < self.state.election_timeout {
+            Box::pin(self.receive_rpc()).await;
+            if self.has_read_quorum() {
+                break;
+            }
+        }
+        if !self.has_read_quorum() {
+            // Couldn't confirm leadership in time; don't leave the client
+            // hanging on a read we can't guarantee is linearizable.
+            self.send_client_response(false, None).await;
+            return;
+        }
+
+        while self.state.last_applied < read_index {
+            Box::pin(self.receive_rpc()).await;
+        }
+
+        let value = self.state_machine.get(key);
+        self.send_client_response(true, value).await;
+    }
+
+    /// Send the outcome of a client request (or read) back on our own
+    /// address, where the client connected in the first place.
+    async fn send_client_response(&mut self, success: bool, value: Option<u32>) {
+        let response = encode_message(&RpcMessage::ClientResponse { success, value });
+        if let Err(e) = self.network_manager.send(self.config.address.as_str(), self.config.port.to_string().as_str(), &response).await {
+            eprintln!("Failed to send client response: {}", e);
+        }
+    }
+
+    async fn handle_request_vote(&mut self, candidate_id: u32, candidate_term: u32, last_log_index: u32, last_log_term: u32) {
         // Only Follower can vote, because Candidate voted for itself
         if self.state.state != RaftState::Follower {
             return;
         }
 
-        let candidate_id = u32::from_be_bytes(data[0..4].try_into().unwrap());
-        let candidate_term = u32::from_be_bytes(data[4..8].try_into().unwrap());
-
         if candidate_term < self.state.current_term {
             return;
         }
 
+        if !self.log_is_at_least_as_up_to_date(last_log_index, last_log_term) {
+            return;
+        }
+
         self.state.voted_for = Some(candidate_id);
 
         // get candidate address from config
         let candidate_address = self.config.id_to_address_mapping.get(&candidate_id);
         if candidate_address.is_none() {
-            // no dynamic membership changes
+            // Candidate hasn't shown up in our applied configuration yet.
             println!("Candidate address not found");
             return;
         }
         let candidate_ip = candidate_address.unwrap().split(":").collect::<Vec<&str>>()[0];
         let candidate_port = candidate_address.unwrap().split(":").collect::<Vec<&str>>()[1];
 
-        let data = [self.id.to_be_bytes(), self.state.current_term.to_be_bytes(), 1u32.to_be_bytes()].concat();
-        let data = [data, 1u32.to_be_bytes().to_vec()].concat();
+        let response = encode_message(&RpcMessage::RequestVoteResponse {
+            voter_id: self.id,
+            term: self.state.current_term,
+            vote_granted: true,
+        });
 
-        let voteresponse = self.network_manager.send(candidate_ip, candidate_port, &data).await;
+        let voteresponse = self.network_manager.send(candidate_ip, candidate_port, &response).await;
         if let Err(e) = voteresponse {
             eprintln!("Failed to send vote response: {}", e);
         }
     }
 
-    async fn handle_request_vote_response(&mut self, data: &[u8]) {
+    async fn handle_request_vote_response(&mut self, voter_id: u32, vote_granted: bool) {
         if self.state.state != RaftState::Candidate {
             return;
         }
 
-        let voter_id = u32::from_be_bytes(data[0..4].try_into().unwrap());
-        let vote_granted = u32::from_be_bytes(data[8..12].try_into().unwrap()) == 1;
-
         self.state.votes_received.insert(voter_id, vote_granted);
     }
 
-    async fn handle_append_entries(&mut self, data: Vec<u8>) {
+    /// A candidate's log is at least as up to date as ours if its last
+    /// entry has a later term, or the same term with an index >= ours.
+    fn log_is_at_least_as_up_to_date(&self, last_log_index: u32, last_log_term: u32) -> bool {
+        let our_last_log_index = self.state.previous_log_index;
+        let our_last_log_term = self.term_at(our_last_log_index);
+        last_log_term > our_last_log_term
+            || (last_log_term == our_last_log_term && last_log_index >= our_last_log_index)
+    }
+
+    /// Grant a pre-vote only if we haven't heard from a leader recently and
+    /// the pre-candidate's log is at least as up to date as ours. Crucially,
+    /// this does not touch `current_term` or `voted_for`.
+    async fn handle_pre_vote(&mut self, candidate_id: u32, term: u32, last_log_index: u32, last_log_term: u32) {
         if self.state.state != RaftState::Follower {
             return;
         }
 
-        let id = u32::from_be_bytes(data[0..4].try_into().unwrap());
-        let leader_term = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if Instant::now().duration_since(self.state.last_heartbeat) < self.state.election_timeout {
+            return;
+        }
 
-        if leader_term < self.state.current_term {
+        if !self.log_is_at_least_as_up_to_date(last_log_index, last_log_term) {
             return;
         }
 
-        let message_type = u32::from_be_bytes(data[8..12].try_into().unwrap());
-        if message_type != 2 {
+        let candidate_address = self.config.id_to_address_mapping.get(&candidate_id);
+        if candidate_address.is_none() {
+            println!("Pre-vote candidate address not found");
             return;
         }
-        
-        let prev_log_index = u32::from_be_bytes(data[12..16].try_into().unwrap());
-        if prev_log_index > self.state.previous_log_index {
-            self.state.previous_log_index = prev_log_index;
-        } else {
+        let candidate_ip = candidate_address.unwrap().split(":").collect::<Vec<&str>>()[0];
+        let candidate_port = candidate_address.unwrap().split(":").collect::<Vec<&str>>()[1];
+
+        let response = encode_message(&RpcMessage::PreVoteResponse {
+            voter_id: self.id,
+            term,
+            vote_granted: true,
+        });
+        if let Err(e) = self.network_manager.send(candidate_ip, candidate_port, &response).await {
+            eprintln!("Failed to send pre-vote response: {}", e);
+        }
+    }
+
+    async fn handle_pre_vote_response(&mut self, voter_id: u32, vote_granted: bool) {
+        if self.state.state != RaftState::PreCandidate {
             return;
         }
 
-        let commit_index = u32::from_be_bytes(data[16..20].try_into().unwrap());
-        if commit_index > self.state.commit_index {
-            self.state.commit_index = commit_index;
-        } else {
+        self.state.votes_received.insert(voter_id, vote_granted);
+    }
+
+    async fn handle_append_entries(&mut self, id: u32, leader_term: u32, prev_log_index: u32, prev_log_term: u32, leader_commit: u32, entries: Vec<LogEntry>) {
+        if self.state.state != RaftState::Follower {
+            return;
+        }
+
+        if leader_term < self.state.current_term {
+            self.send_append_entries_response(id, false).await;
             return;
         }
 
-        let data = &data[20..];
-        let _ = self.append_log(id, leader_term, data).await;
+        if prev_log_index > 0 && prev_log_index > self.state.last_included_index {
+            match self.entry_at(prev_log_index) {
+                Some(entry) if entry.term == prev_log_term => {}
+                _ => {
+                    println!("Rejecting append entries: no entry at index {} with term {}", prev_log_index, prev_log_term);
+                    self.send_append_entries_response(id, false).await;
+                    return;
+                }
+            }
+        }
 
-        self.state.current_term += 1; // increment term on successful append for follower
-        
-        let response = [self.id.to_be_bytes(), self.state.current_term.to_be_bytes(), 3u32.to_be_bytes(), 1u32.to_be_bytes()].concat();
-        let leader_address = self.config.id_to_address_mapping.get(&id).unwrap();
+        let mut last_new_index = prev_log_index;
+        for entry in entries {
+            last_new_index = entry.index;
+            match self.entry_at(entry.index) {
+                Some(existing) if existing.term == entry.term => {
+                    // already have this entry
+                }
+                Some(_) => {
+                    // conflicting entry: truncate the log (and on-disk storage) from here onward
+                    self.truncate_log_from(entry.index).await;
+                    self.append_log(vec![entry]).await;
+                }
+                None => {
+                    self.append_log(vec![entry]).await;
+                }
+            }
+        }
+
+        self.state.previous_log_index = self.state.previous_log_index.max(last_new_index);
+
+        if leader_commit > self.state.commit_index {
+            self.state.commit_index = leader_commit.min(last_new_index);
+        }
+
+        self.state.current_term = leader_term;
+        self.send_append_entries_response(id, true).await;
+        self.apply_config_entries_up_to(self.state.commit_index);
+        self.apply_committed_entries().await;
+        self.compact_log_if_needed().await;
+    }
+
+    async fn send_append_entries_response(&mut self, leader_id: u32, success: bool) {
+        let response = encode_message(&RpcMessage::AppendEntriesResponse {
+            sender_id: self.id,
+            term: self.state.current_term,
+            success,
+        });
+        let leader_address = self.config.id_to_address_mapping.get(&leader_id).unwrap();
         let leader_ip = leader_address.split(":").collect::<Vec<&str>>()[0];
         let leader_port = leader_address.split(":").collect::<Vec<&str>>()[1];
-        println!("Sending append entries response to leader: {}", id);
+        println!("Sending append entries response ({}) to leader: {}", success, leader_id);
         if let Err(e) = self.network_manager.send(leader_ip, leader_port, &response).await {
             eprintln!("Failed to send append entries response: {}", e);
         }
-
     }
 
-    async fn handle_append_entries_response(&mut self, data: &[u8]) {
+    async fn handle_append_entries_response(&mut self, sender_id: u32, term: u32, success: bool) {
         if self.state.state != RaftState::Leader {
             return;
         }
-        
-        let sender_id = u32::from_be_bytes(data[0..4].try_into().unwrap());
-        let term = u32::from_be_bytes(data[4..8].try_into().unwrap());
-        let success = u32::from_be_bytes(data[12..16].try_into().unwrap()) == 1;
+
         println!("Append entries response from peer: {}", sender_id);
         println!("Success: {}", success);
         println!("Term: {}", term);
@@ -452,51 +811,435 @@ impl Server {
         if success {
             // check if you got a quorum
             let last_log_index = self.state.previous_log_index;
-            self.state.match_index[sender_id as usize - 1] = last_log_index;
-            self.state.next_index[sender_id as usize - 1] = last_log_index + 1;
+            self.state.match_index.insert(sender_id, last_log_index);
+            self.state.next_index.insert(sender_id, last_log_index + 1);
 
-            let mut match_indices = self.state.match_index.clone();
-            match_indices.sort();
-            let quorum_index = match_indices[self.peers.len() / 2];
+            let quorum_index = self.quorum_commit_index();
             println!("Quorum index: {}", quorum_index);
             println!("Match indices: {:?}", self.state.match_index);
             println!("Next indices: {:?}", self.state.next_index);
             println!("commit index: {}", self.state.commit_index);
             if quorum_index >= self.state.commit_index {
                 self.state.commit_index = quorum_index;
-                // return client response
-                let response_data = [self.id.to_be_bytes(), self.state.current_term.to_be_bytes(), 10u32.to_be_bytes(), 1u32.to_be_bytes()].concat();
-                if let Err(e) = self.network_manager.send(self.config.address.as_str(), self.config.port.to_string().as_str(), &response_data).await {
-                    eprintln!("Failed to send client response: {}", e);
-                }
                 println!("match index: {:?}", self.state.match_index);
                 println!("commit index: {}", self.state.commit_index);
                 println!("quorum index: {}", quorum_index);
+                self.apply_config_entries_up_to(self.state.commit_index);
+                let applied_value = self.apply_committed_entries().await;
+                self.compact_log_if_needed().await;
+                self.send_client_response(true, applied_value).await;
                 println!("Client response sent");
             }
         } else {
-            self.state.next_index[sender_id as usize - 1] -= 1;
+            let next_index = self.state.next_index.entry(sender_id).or_insert(0);
+            if *next_index > 0 {
+                *next_index -= 1;
+            }
+            self.retry_append_entries(sender_id).await;
+        }
+    }
+
+    /// Index acknowledged by a majority. During a joint configuration this
+    /// requires separate majorities in both the old and new member sets.
+    fn quorum_commit_index(&self) -> u32 {
+        match &self.state.joint_config {
+            Some(cfg) => {
+                let old_quorum = self.committed_index_for(&cfg.old_members);
+                let new_quorum = self.committed_index_for(&cfg.new_members);
+                old_quorum.min(new_quorum)
+            }
+            None => {
+                let mut members = self.peers.clone();
+                members.push(self.id);
+                self.committed_index_for(&members)
+            }
         }
     }
 
-    async fn handle_heartbeat(&mut self) {
+    fn committed_index_for(&self, members: &[u32]) -> u32 {
+        let mut indices: Vec<u32> = members.iter().map(|&id| {
+            if id == self.id {
+                self.state.previous_log_index
+            } else {
+                *self.state.match_index.get(&id).unwrap_or(&0)
+            }
+        }).collect();
+        indices.sort();
+        // A true majority needs `members.len() / 2 + 1` members at or above
+        // the chosen index; with ascending order that's this many from the
+        // end, i.e. index `len - (len / 2 + 1)`. `len / 2` alone under-counts
+        // whenever `len` is even (e.g. for 4 members it picks the 3rd-lowest
+        // value, which only 2 of 4 members are guaranteed to meet).
+        indices[members.len() - (members.len() / 2 + 1)]
+    }
+
+    /// Mirrors `quorum_commit_index` for pre-commit vote counting: during a
+    /// joint configuration a candidate needs a majority in both member sets.
+    fn has_vote_quorum(&self) -> bool {
+        match &self.state.joint_config {
+            Some(cfg) => self.has_majority(&cfg.old_members) && self.has_majority(&cfg.new_members),
+            None => {
+                let votes = self.state.votes_received.values().filter(|&&granted| granted).count() as u32;
+                self.is_quorum(votes)
+            }
+        }
+    }
+
+    fn has_majority(&self, members: &[u32]) -> bool {
+        let votes = members.iter().filter(|&&id| *self.state.votes_received.get(&id).unwrap_or(&false)).count() as u32;
+        votes > (members.len() / 2) as u32
+    }
+
+    /// Mirrors `has_vote_quorum` for ReadIndex: do the acks of the current
+    /// heartbeat round, our own included, form a quorum?
+    fn has_read_quorum(&self) -> bool {
+        match &self.state.joint_config {
+            Some(cfg) => self.has_read_majority(&cfg.old_members) && self.has_read_majority(&cfg.new_members),
+            None => {
+                let acks = self.state.read_index_acks.values().filter(|&&acked| acked).count() as u32;
+                self.is_quorum(acks)
+            }
+        }
+    }
+
+    fn has_read_majority(&self, members: &[u32]) -> bool {
+        let acks = members.iter().filter(|&&id| *self.state.read_index_acks.get(&id).unwrap_or(&false)).count() as u32;
+        acks > (members.len() / 2) as u32
+    }
+
+    async fn retry_append_entries(&mut self, peer_id: u32) {
+        let next_index = *self.state.next_index.get(&peer_id).unwrap_or(&0);
+
+        if self.state.last_included_index > 0 && next_index <= self.state.last_included_index {
+            self.send_install_snapshot(peer_id).await;
+            return;
+        }
+
+        let prev_log_index = next_index.saturating_sub(1);
+        let prev_log_term = self.term_at(prev_log_index);
+        let entries: Vec<LogEntry> = self.state.log.iter().filter(|entry| entry.index >= next_index).cloned().collect();
+
+        let data = self.prepare_append_batch(self.id, self.state.current_term, prev_log_index, prev_log_term, self.state.commit_index, entries);
+        let address = self.config.id_to_address_mapping.get(&peer_id).unwrap();
+        let ip = address.split(":").collect::<Vec<&str>>()[0];
+        let port = address.split(":").collect::<Vec<&str>>()[1];
+        println!("Retrying append entries to peer {} from index {}", peer_id, next_index);
+        if let Err(e) = self.network_manager.send(ip, port, &data).await {
+            eprintln!("Failed to retry append entries to peer {}: {}", peer_id, e);
+        }
+    }
+
+    async fn send_install_snapshot(&mut self, peer_id: u32) {
+        let snapshot = match self.storage.load_snapshot().await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => {
+                eprintln!("No snapshot available to catch up peer {}", peer_id);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Failed to load snapshot for peer {}: {}", peer_id, e);
+                return;
+            }
+        };
+        let snapshot: Snapshot = bincode::deserialize(&snapshot).unwrap();
+
+        let data = encode_message(&RpcMessage::InstallSnapshot {
+            leader_id: self.id,
+            term: self.state.current_term,
+            last_included_index: snapshot.last_included_index,
+            last_included_term: snapshot.last_included_term,
+            state: snapshot.state,
+            cluster_config: snapshot.cluster_config,
+        });
+        let address = self.config.id_to_address_mapping.get(&peer_id).unwrap();
+        let ip = address.split(":").collect::<Vec<&str>>()[0];
+        let port = address.split(":").collect::<Vec<&str>>()[1];
+        println!("Sending InstallSnapshot to peer {} up to index {}", peer_id, snapshot.last_included_index);
+        if let Err(e) = self.network_manager.send(ip, port, &data).await {
+            eprintln!("Failed to send install snapshot to peer {}: {}", peer_id, e);
+        }
+    }
+
+    async fn handle_install_snapshot(&mut self, leader_id: u32, term: u32, last_included_index: u32, last_included_term: u32, state: Vec<u8>, cluster_config: Option<ClusterConfig>) {
+        if self.state.state != RaftState::Follower {
+            return;
+        }
+        if term < self.state.current_term {
+            return;
+        }
+        if last_included_index <= self.state.last_included_index {
+            // Stale or duplicate (e.g. redelivered) install: we've already
+            // applied at least this far, so acknowledge without restoring
+            // the state machine or rewinding last_included_index/term
+            // backwards.
+            let response = encode_message(&RpcMessage::InstallSnapshotResponse {
+                sender_id: self.id,
+                term: self.state.current_term,
+                success: true,
+            });
+            let leader_address = self.config.id_to_address_mapping.get(&leader_id).unwrap();
+            let leader_ip = leader_address.split(":").collect::<Vec<&str>>()[0];
+            let leader_port = leader_address.split(":").collect::<Vec<&str>>()[1];
+            if let Err(e) = self.network_manager.send(leader_ip, leader_port, &response).await {
+                eprintln!("Failed to send install snapshot response: {}", e);
+            }
+            return;
+        }
+
+        let snapshot = Snapshot { last_included_index, last_included_term, state: state.clone(), cluster_config: cluster_config.clone() };
+        let serialized = bincode::serialize(&snapshot).unwrap();
+        if let Err(e) = self.storage.store_snapshot(&serialized).await {
+            eprintln!("Failed to persist installed snapshot: {}", e);
+            return;
+        }
+
+        // Discard any conflicting log prefix and reset the state machine to the snapshot.
+        self.state.log.retain(|entry| entry.index > last_included_index);
+        if let Err(e) = self.storage.compact_prefix(last_included_index as u64).await {
+            eprintln!("Failed to prune on-disk log up to index {}: {}", last_included_index, e);
+        }
+        self.state.last_included_index = last_included_index;
+        self.state.last_included_term = last_included_term;
+        self.state.previous_log_index = self.state.previous_log_index.max(last_included_index);
+        self.state.commit_index = self.state.commit_index.max(last_included_index);
+        self.state.last_applied = self.state.last_applied.max(last_included_index);
+        self.state_machine.restore(&state);
+        self.state.current_term = term;
+
+        // Any ConfigChange entries covered by the compacted range are gone
+        // from the log, so adopt the membership the snapshot carries instead
+        // of silently leaving `peers`/`id_to_address_mapping` stale.
+        if let Some(config) = &cluster_config {
+            self.apply_cluster_config(config);
+        }
+        self.state.last_applied_config_index = self.state.last_applied_config_index.max(last_included_index);
+
+        let response = encode_message(&RpcMessage::InstallSnapshotResponse {
+            sender_id: self.id,
+            term: self.state.current_term,
+            success: true,
+        });
+        let leader_address = self.config.id_to_address_mapping.get(&leader_id).unwrap();
+        let leader_ip = leader_address.split(":").collect::<Vec<&str>>()[0];
+        let leader_port = leader_address.split(":").collect::<Vec<&str>>()[1];
+        println!("Installed snapshot up to index {} from leader: {}", last_included_index, leader_id);
+        if let Err(e) = self.network_manager.send(leader_ip, leader_port, &response).await {
+            eprintln!("Failed to send install snapshot response: {}", e);
+        }
+    }
+
+    async fn handle_install_snapshot_response(&mut self, sender_id: u32, term: u32, success: bool) {
+        if self.state.state != RaftState::Leader {
+            return;
+        }
+        if term > self.state.current_term {
+            return;
+        }
+        if success {
+            self.state.match_index.insert(sender_id, self.state.last_included_index);
+            self.state.next_index.insert(sender_id, self.state.last_included_index + 1);
+        }
+    }
+
+    /// Once more than `snapshot_threshold` entries have been committed since
+    /// the last snapshot, serialize the state machine and discard the log
+    /// prefix it covers.
+    async fn compact_log_if_needed(&mut self) {
+        if self.state.commit_index <= self.state.last_included_index {
+            return;
+        }
+        if self.state.commit_index - self.state.last_included_index <= self.config.snapshot_threshold {
+            return;
+        }
+
+        let last_included_index = self.state.commit_index;
+        let last_included_term = self.term_at(last_included_index);
+        let snapshot = Snapshot {
+            last_included_index,
+            last_included_term,
+            state: self.state_machine.snapshot(),
+            cluster_config: self.state.current_cluster_config.clone(),
+        };
+
+        let serialized = bincode::serialize(&snapshot).unwrap();
+        if let Err(e) = self.storage.store_snapshot(&serialized).await {
+            eprintln!("Failed to persist snapshot up to index {}: {}", last_included_index, e);
+            return;
+        }
+
+        self.state.log.retain(|entry| entry.index > last_included_index);
+        self.state.last_included_index = last_included_index;
+        self.state.last_included_term = last_included_term;
+        if let Err(e) = self.storage.compact_prefix(last_included_index as u64).await {
+            eprintln!("Failed to prune on-disk log up to index {}: {}", last_included_index, e);
+        }
+        println!("Compacted log up to index {} (term {})", last_included_index, last_included_term);
+    }
+
+    /// Propose adding `id`/`address` to the cluster. Only the leader can
+    /// propose membership changes, and only one change may be in flight at
+    /// a time (no joint config already committing).
+    pub async fn add_server(&mut self, id: u32, address: String) {
+        let address_update = Some((id, address));
+        self.propose_config_change(move |old_members| {
+            let mut new_members = old_members.clone();
+            if !new_members.contains(&id) {
+                new_members.push(id);
+            }
+            new_members
+        }, address_update).await;
+    }
+
+    /// Propose removing `id` from the cluster.
+    pub async fn remove_server(&mut self, id: u32) {
+        self.propose_config_change(move |old_members| {
+            old_members.iter().cloned().filter(|&member| member != id).collect()
+        }, None).await;
+    }
+
+    async fn propose_config_change(&mut self, compute_new_members: impl Fn(&Vec<u32>) -> Vec<u32>, address_update: Option<(u32, String)>) {
+        if self.state.state != RaftState::Leader {
+            eprintln!("Only the leader can propose configuration changes");
+            return;
+        }
+        if self.state.joint_config.is_some() {
+            eprintln!("A configuration change is already in progress");
+            return;
+        }
+
+        let mut old_members = self.peers.clone();
+        old_members.push(self.id);
+        old_members.sort();
+
+        let new_members = compute_new_members(&old_members);
+
+        let mut id_to_address_mapping = self.config.id_to_address_mapping.clone();
+        if let Some((id, address)) = address_update {
+            id_to_address_mapping.insert(id, address);
+        }
+
+        let joint = ClusterConfig { old_members, new_members, id_to_address_mapping };
+        self.append_config_entry(joint.clone());
+        self.state.joint_config = Some(joint);
+    }
+
+    fn append_config_entry(&mut self, config: ClusterConfig) {
+        self.state.previous_log_index += 1;
+        let entry = LogEntry {
+            index: self.state.previous_log_index,
+            leader_id: self.id,
+            server_id: self.id,
+            term: self.state.current_term,
+            command: LogCommand::ConfigChange(config),
+            key: 0,
+            data: 0,
+        };
+        println!("Proposing configuration change: {:?}", entry);
+        self.write_buffer.push(entry);
+    }
+
+    /// Apply any committed `ConfigChange` entries up to `commit_index`.
+    /// Apart from adopting the configuration an installed snapshot carries
+    /// (see `handle_install_snapshot`), membership, `peers`, and
+    /// `id_to_address_mapping` only change here, once a configuration entry
+    /// is known to be committed, never when it is merely received.
+    fn apply_config_entries_up_to(&mut self, commit_index: u32) {
+        while self.state.last_applied_config_index < commit_index {
+            let next_index = self.state.last_applied_config_index + 1;
+            let command = self.entry_at(next_index).map(|entry| entry.command.clone());
+            self.state.last_applied_config_index = next_index;
+            if let Some(LogCommand::ConfigChange(config)) = command {
+                self.apply_cluster_config(&config);
+            }
+        }
+    }
+
+    fn apply_cluster_config(&mut self, config: &ClusterConfig) {
+        let is_joint = config.old_members != config.new_members;
+        self.config.id_to_address_mapping = config.id_to_address_mapping.clone();
+        self.state.current_cluster_config = Some(config.clone());
+
+        let members = if is_joint {
+            let mut union: Vec<u32> = config.old_members.iter().chain(config.new_members.iter()).cloned().collect();
+            union.sort();
+            union.dedup();
+            union
+        } else {
+            config.new_members.clone()
+        };
+        self.peers = members.into_iter().filter(|&member| member != self.id).collect();
+
+        if is_joint {
+            self.state.joint_config = Some(config.clone());
+            if self.state.state == RaftState::Leader {
+                // The joint entry just committed; finalize the configuration.
+                let final_config = ClusterConfig {
+                    old_members: config.new_members.clone(),
+                    new_members: config.new_members.clone(),
+                    id_to_address_mapping: config.id_to_address_mapping.clone(),
+                };
+                self.append_config_entry(final_config);
+            }
+        } else {
+            self.state.joint_config = None;
+            if !config.new_members.contains(&self.id) {
+                println!("Server {} removed from cluster configuration; stepping down", self.id);
+                self.state.state = RaftState::Follower;
+            }
+        }
+    }
+
+    async fn handle_heartbeat(&mut self, leader_id: u32) {
         if self.state.state != RaftState::Follower {
             return;
         }
         self.state.last_heartbeat = Instant::now();
+
+        let response = encode_message(&RpcMessage::HeartbeatResponse {
+            sender_id: self.id,
+            term: self.state.current_term,
+        });
+        let leader_address = self.config.id_to_address_mapping.get(&leader_id).unwrap();
+        let leader_ip = leader_address.split(":").collect::<Vec<&str>>()[0];
+        let leader_port = leader_address.split(":").collect::<Vec<&str>>()[1];
+        if let Err(e) = self.network_manager.send(leader_ip, leader_port, &response).await {
+            eprintln!("Failed to send heartbeat response: {}", e);
+        }
     }
 
-    async fn handle_heartbeat_response(&mut self) {
-        // Noop
+    /// Track heartbeat acks toward the quorum a ReadIndex round is waiting on.
+    async fn handle_heartbeat_response(&mut self, sender_id: u32) {
+        if self.state.state != RaftState::Leader {
+            return;
+        }
+        self.state.read_index_acks.insert(sender_id, true);
     }
 
-    async fn append_log(&mut self, id: u32, term: u32, data: &[u8]) {
-        println!("Appending logs to disk from peer: {} to server: {}", id, self.id);
-        println!("Data: {:?}", data);
+    /// Apply newly committed log entries (after `last_applied`) to the state
+    /// machine in order, persisting the watermark to `storage` for whenever
+    /// crash recovery reads it back (nothing does yet — `Server::new`/`start`
+    /// always boot from empty state). Returns the value produced by the last
+    /// entry applied, if any.
+    async fn apply_committed_entries(&mut self) -> Option<u32> {
+        let mut last_value = None;
+        while self.state.last_applied < self.state.commit_index {
+            let next_index = self.state.last_applied + 1;
+            let applied = self.entry_at(next_index).map(|entry| (entry.command.clone(), entry.key, entry.data));
+            self.state.last_applied = next_index;
+            if let Some((command, key, value)) = applied {
+                last_value = self.state_machine.apply(&command, key, value);
+            }
+            if let Err(e) = self.storage.store_last_applied(self.state.last_applied as u64).await {
+                eprintln!("Failed to persist last applied index {}: {}", self.state.last_applied, e);
+            }
+        }
+        last_value
+    }
 
-        let log_entries = self.deserialize_log_entries(id, term, data);
+    async fn append_log(&mut self, entries: Vec<LogEntry>) {
+        println!("Appending {} log entries on server: {}", entries.len(), self.id);
 
-        for entry in log_entries {
+        for entry in entries {
             self.state.log.push(entry.clone());
             let serialized_entry = bincode::serialize(&entry).unwrap();
             if let Err(e) = self.storage.store(&serialized_entry).await {
@@ -507,31 +1250,26 @@ impl Server {
         println!("Log after appending: {:?}", self.state.log);
     }
 
-    fn deserialize_log_entries(&self, sender_id: u32, term: u32, data: &[u8]) -> Vec<LogEntry> {
-        let mut entries = Vec::new();
-        let mut index = 0;
-        while index < data.len() {
-            let command_type = u32::from_be_bytes(data[index..index + 4].try_into().unwrap());
-            index += 4;
-            let command = match command_type {
-                0 => LogCommand::Noop,
-                1 => LogCommand::Set,
-                2 => LogCommand::Delete,
-                _ => panic!("Invalid command type"),
-            };
-            let entry_data = u32::from_be_bytes(data[index..index + 4].try_into().unwrap());
-            index += 4;
-
-            let entry = LogEntry {
-                leader_id: sender_id,
-                server_id: self.id,
-                term,
-                command,
-                data: entry_data,
-            };
-            entries.push(entry);
-        }
-        entries
+    async fn truncate_log_from(&mut self, index: u32) {
+        println!("Truncating log from index {} on server: {}", index, self.id);
+        self.state.log.retain(|entry| entry.index < index);
+        if let Err(e) = self.storage.truncate_from(index as u64).await {
+            eprintln!("Failed to truncate on-disk log from index {}: {}", index, e);
+        }
+    }
+
+    fn entry_at(&self, index: u32) -> Option<&LogEntry> {
+        self.state.log.iter().find(|entry| entry.index == index)
+    }
+
+    fn term_at(&self, index: u32) -> u32 {
+        if index == 0 {
+            return 0;
+        }
+        if index == self.state.last_included_index {
+            return self.state.last_included_term;
+        }
+        self.entry_at(index).map(|entry| entry.term).unwrap_or(0)
     }
 
     fn is_quorum(&self, votes: u32) -> bool {
@@ -544,3 +1282,202 @@ impl Server {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server(id: u32, cluster_nodes: Vec<u32>) -> Server {
+        let mut id_to_address_mapping = HashMap::new();
+        for &node in &cluster_nodes {
+            id_to_address_mapping.insert(node, format!("127.0.0.1:{}", 9000 + node));
+        }
+        let config = ServerConfig {
+            election_timeout: Duration::from_millis(150),
+            address: "127.0.0.1".to_string(),
+            port: 9000 + id as u16,
+            cluster_nodes,
+            id_to_address_mapping,
+            default_leader: None,
+            leadership_preferences: HashMap::new(),
+            snapshot_threshold: 100,
+        };
+        Server::new(id, config)
+    }
+
+    #[test]
+    fn committed_index_for_requires_true_majority_in_even_sized_clusters() {
+        let mut server = test_server(1, vec![1, 2, 3, 4]);
+        server.state.previous_log_index = 5;
+        server.state.match_index.insert(2, 5);
+        server.state.match_index.insert(3, 5);
+        server.state.match_index.insert(4, 1);
+        // Sorted match indices for [1,2,3,4] are [1,5,5,5]; a true majority
+        // (3 of 4) requires the second-lowest value, which is 5.
+        assert_eq!(server.committed_index_for(&[1, 2, 3, 4]), 5);
+    }
+
+    #[test]
+    fn committed_index_for_odd_sized_cluster_uses_middle_value() {
+        let mut server = test_server(1, vec![1, 2, 3]);
+        server.state.previous_log_index = 7;
+        server.state.match_index.insert(2, 5);
+        server.state.match_index.insert(3, 2);
+        // Sorted [2,5,7]; majority (2 of 3) requires the middle value, 5.
+        assert_eq!(server.committed_index_for(&[1, 2, 3]), 5);
+    }
+
+    #[test]
+    fn has_vote_quorum_requires_majority_of_the_whole_cluster() {
+        let mut server = test_server(1, vec![1, 2, 3, 4, 5]);
+        server.state.votes_received.insert(1, true);
+        server.state.votes_received.insert(2, true);
+        assert!(!server.has_vote_quorum());
+
+        server.state.votes_received.insert(3, true);
+        assert!(server.has_vote_quorum());
+    }
+
+    #[test]
+    fn pre_vote_round_promotes_to_candidate_on_quorum_without_clearing_votes() {
+        let mut server = test_server(1, vec![1, 2, 3]);
+        server.state.state = RaftState::PreCandidate;
+        server.state.votes_received.insert(1, true);
+        server.state.votes_received.insert(2, true);
+
+        server.conclude_pre_vote_round();
+
+        assert_eq!(server.state.state, RaftState::Candidate);
+        // Votes are carried over into `candidate()`, which also votes for
+        // itself and counts existing entries toward quorum there.
+        assert_eq!(server.state.votes_received.len(), 2);
+    }
+
+    #[test]
+    fn pre_vote_round_falls_back_to_follower_and_clears_votes_without_quorum() {
+        let mut server = test_server(1, vec![1, 2, 3, 4, 5]);
+        server.state.state = RaftState::PreCandidate;
+        server.state.votes_received.insert(1, true);
+
+        server.conclude_pre_vote_round();
+
+        assert_eq!(server.state.state, RaftState::Follower);
+        assert!(server.state.votes_received.is_empty());
+    }
+
+    #[test]
+    fn append_entries_round_trips_through_encode_decode() {
+        let message = RpcMessage::AppendEntries {
+            leader_id: 1,
+            term: 4,
+            prev_log_index: 2,
+            prev_log_term: 3,
+            commit_index: 2,
+            entries: vec![],
+        };
+        let encoded = encode_message(&message);
+        let decoded = decode_message(&encoded).unwrap();
+        assert_eq!(decoded.term(), 4);
+        match decoded {
+            RpcMessage::AppendEntries { leader_id, prev_log_index, .. } => {
+                assert_eq!(leader_id, 1);
+                assert_eq!(prev_log_index, 2);
+            }
+            other => panic!("expected AppendEntries, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_response_round_trips_through_encode_decode() {
+        let message = RpcMessage::ClientResponse { success: false, value: Some(42) };
+        let encoded = encode_message(&message);
+        let decoded = decode_message(&encoded).unwrap();
+        match decoded {
+            RpcMessage::ClientResponse { success, value } => {
+                assert!(!success);
+                assert_eq!(value, Some(42));
+            }
+            other => panic!("expected ClientResponse, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_server_replicates_finalizing_entry_instead_of_dropping_it() {
+        let mut server = test_server(1, vec![1, 2, 3]);
+        server.state.state = RaftState::Leader;
+
+        server.add_server(4, "127.0.0.1:9004".to_string()).await;
+
+        // Proposing the change only enqueues the joint C_old,new entry; it
+        // hasn't been committed yet, so membership hasn't changed.
+        assert_eq!(server.write_buffer.len(), 1);
+        assert!(server.state.joint_config.is_some());
+        assert!(!server.peers.contains(&4));
+        let joint_entry = server.write_buffer[0].clone();
+
+        // Simulate the entry being sent and committed by a quorum: this is
+        // what drives handle_append_entries_response -> apply_cluster_config
+        // -> append_config_entry to push the finalizing C_new entry onto
+        // write_buffer *while* the leader's consensus-wait loop is still
+        // running, which is exactly what the old unconditional
+        // write_buffer.clear() used to destroy.
+        server.append_log(vec![joint_entry.clone()]).await;
+        server.write_buffer.retain(|entry| entry.index != joint_entry.index);
+        server.state.commit_index = joint_entry.index;
+        server.apply_config_entries_up_to(server.state.commit_index);
+
+        assert!(server.peers.contains(&4), "joint config should already admit the new member");
+        assert!(server.state.joint_config.is_some(), "still joint until C_new commits");
+        assert_eq!(
+            server.write_buffer.len(),
+            1,
+            "finalizing C_new entry must survive, not be dropped"
+        );
+        let final_entry = server.write_buffer[0].clone();
+
+        // Commit the finalizing entry the same way.
+        server.append_log(vec![final_entry.clone()]).await;
+        server.write_buffer.retain(|entry| entry.index != final_entry.index);
+        server.state.commit_index = final_entry.index;
+        server.apply_config_entries_up_to(server.state.commit_index);
+
+        assert!(server.state.joint_config.is_none());
+        assert!(server.peers.contains(&4));
+        assert!(server.write_buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_install_snapshot_ignores_stale_or_duplicate_snapshots() {
+        let mut server = test_server(2, vec![1, 2, 3]);
+        server.state.state = RaftState::Follower;
+        server.state.last_included_index = 10;
+        server.state.last_included_term = 3;
+        server.state_machine.apply(&LogCommand::Set, 1, 99);
+
+        // A redelivered or out-of-order InstallSnapshot covering an index we
+        // already applied must be acked without rewinding our state.
+        server.handle_install_snapshot(1, 3, 10, 3, vec![], None).await;
+        assert_eq!(server.state.last_included_index, 10);
+        assert_eq!(server.state.last_included_term, 3);
+        assert_eq!(server.state_machine.get(1), Some(99));
+
+        server.handle_install_snapshot(1, 3, 5, 2, vec![], None).await;
+        assert_eq!(server.state.last_included_index, 10);
+        assert_eq!(server.state.last_included_term, 3);
+        assert_eq!(server.state_machine.get(1), Some(99));
+    }
+
+    #[test]
+    fn decode_message_rejects_frame_whose_length_prefix_does_not_match() {
+        let message = RpcMessage::Heartbeat { leader_id: 1, term: 1 };
+
+        let mut truncated = encode_message(&message);
+        truncated.pop();
+        decode_message(&truncated).expect_err("payload shorter than the declared length should not decode");
+
+        let mut corrupted = encode_message(&message);
+        let bogus_len = (corrupted.len() - 8 + 1) as u32;
+        corrupted[4..8].copy_from_slice(&bogus_len.to_be_bytes());
+        decode_message(&corrupted).expect_err("mismatched length prefix should not decode");
+    }
+}